@@ -3,16 +3,18 @@
 //!
 //! The code generation is rather simple but it relies heavily on type inference.
 
-use crate::parser::{FuncDef, ImplBlockDef};
+use crate::parser::{FuncDef, GlobalDef, ImplBlockDef, MemoryDef, TableDef};
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::{quote, quote_spanned, ToTokens};
 
 pub fn codegen(ext_def: &ImplBlockDef, to: &mut TokenStream) {
     let mut externals = TokenStream::new();
     let mut module_resolver = TokenStream::new();
+    let mut abi_digest = TokenStream::new();
 
     derive_externals(ext_def, &mut externals);
     derive_module_resolver(ext_def, &mut module_resolver);
+    derive_abi_digest(ext_def, &mut abi_digest);
 
     let (impl_generics, _, where_clause) = ext_def.generics.split_for_impl();
     let ty = &ext_def.ty;
@@ -23,26 +25,52 @@ pub fn codegen(ext_def: &ImplBlockDef, to: &mut TokenStream) {
                 extern crate wasmi as _wasmi;
 
                 use _wasmi::{
-                    Trap, RuntimeValue, RuntimeArgs, Externals, ValueType, ModuleImportResolver,
-                    Signature, FuncRef, Error, FuncInstance,
+                    Trap, TrapCode, HostError, RuntimeValue, RuntimeArgs, Externals, ValueType,
+                    ModuleImportResolver, Signature, FuncRef, Error, FuncInstance,
+                    GlobalRef, MemoryRef, TableRef,
+                    GlobalDescriptor, MemoryDescriptor, TableDescriptor,
                     derive_support::{
                         IntoWasmResult,
                         IntoWasmValue,
+                        Digest,
+                        Sha3_256,
                     },
                 };
 
                 #[inline(always)]
-                fn materialize_arg_ty<W: IntoWasmValue>(_w: Option<W>) -> ValueType {
-                    W::VALUE_TYPE
+                fn materialize_arg_tys<W: IntoWasmValue>(_w: Option<W>) -> &'static [ValueType] {
+                    W::VALUE_TYPES
                 }
 
                 #[inline(always)]
-                fn materialize_ret_type<W: IntoWasmResult>(_w: Option<W>) -> Option<ValueType> {
-                    W::VALUE_TYPE
+                fn materialize_ret_tys<W: IntoWasmResult>(_w: Option<W>) -> &'static [ValueType] {
+                    W::VALUE_TYPES
+                }
+
+                #[derive(Debug)]
+                struct UnknownHostFunctionIndex(usize);
+
+                impl ::core::fmt::Display for UnknownHostFunctionIndex {
+                    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+                        write!(f, "host function with index {} is undefined", self.0)
+                    }
+                }
+
+                impl HostError for UnknownHostFunctionIndex {}
+
+                #[inline(always)]
+                fn value_type_tag(ty: ValueType) -> u8 {
+                    match ty {
+                        ValueType::I32 => 1,
+                        ValueType::I64 => 2,
+                        ValueType::F32 => 3,
+                        ValueType::F64 => 4,
+                    }
                 }
 
                 #externals
                 #module_resolver
+                #abi_digest
             };
         }
     })
@@ -53,16 +81,19 @@ fn emit_dispatch_func_arm(func: &FuncDef) -> TokenStream {
     let index = func.index as usize;
     let return_ty_span = func.return_ty.clone().unwrap_or_else(|| Span::call_site());
 
+    // Each parameter pulls as many slots off `args` as its type declares
+    // (`IntoWasmValue::from_wasm_args`), which lets aggregate/newtype
+    // parameters unpack across several consecutive wasm slots.
     let mut unmarshall_args = TokenStream::new();
     for param in &func.params {
         let param_span = param.ident.span();
         let ident = &param.ident;
 
         (quote_spanned! {param_span=>
-            let #ident =
-                args.next()
-                    .and_then(|rt_val| rt_val.try_into())
-                    .unwrap();
+            let #ident = match IntoWasmValue::from_wasm_args(&mut args) {
+                Some(value) => value,
+                None => return Err(Trap::new(TrapCode::UnexpectedSignature)),
+            };
         })
         .to_tokens(&mut unmarshall_args);
     }
@@ -71,6 +102,13 @@ fn emit_dispatch_func_arm(func: &FuncDef) -> TokenStream {
         let mut args = args.as_ref().iter();
         #unmarshall_args
     };
+    // `r` may be a plain value, a tuple (mapped to multiple wasm return
+    // values), or a `Result<T, E>` (`E: HostError`, e.g. `Trap` itself) —
+    // `IntoWasmResult::into_wasm_result` is responsible both for flattening
+    // `r` into the `Vec<RuntimeValue>` this arm returns and for folding the
+    // `Err` case into the `Trap` this arm returns, so a host method can
+    // signal a trap just by returning `Err(..)`. That conversion lives in the
+    // `derive_support` impls, not in this crate.
     let epilogue = quote_spanned! {return_ty_span=>
         IntoWasmResult::into_wasm_result(r)
     };
@@ -102,14 +140,20 @@ fn derive_externals(ext_def: &ImplBlockDef, to: &mut TokenStream) {
 
     (quote::quote! {
         impl #impl_generics Externals for #ty #where_clause {
+            // `Vec<RuntimeValue>` (rather than `Option<RuntimeValue>`) lets a
+            // host method return a tuple that maps to a multi-value wasm
+            // result: zero entries is no return value, one is the common
+            // single-value case, and more than one is a genuine multi-value
+            // result, matching what `emit_resolve_func_arm` validates against
+            // `signature.return_types()`.
             fn invoke_index(
                 &mut self,
                 index: usize,
                 args: RuntimeArgs,
-            ) -> Result<Option<RuntimeValue>, Trap> {
+            ) -> Result<Vec<RuntimeValue>, Trap> {
                 match index {
                     #(#match_arms),*
-                    _ => panic!("fn with index {} is undefined", index),
+                    _ => Err(Trap::new(TrapCode::Host(Box::new(UnknownHostFunctionIndex(index))))),
                 }
             }
 
@@ -119,11 +163,13 @@ fn derive_externals(ext_def: &ImplBlockDef, to: &mut TokenStream) {
     .to_tokens(to);
 }
 
-fn emit_resolve_func_arm(func: &FuncDef) -> TokenStream {
-    let index = func.index as usize;
-    let string_ident = &func.name;
-    let return_ty_span = func.return_ty.clone().unwrap_or_else(|| Span::call_site());
-
+// Builds the shared "call `Self::#name` from inside `if false {}`" scaffolding
+// that both `emit_resolve_func_arm` and `emit_digest_func_block` rely on:
+// every parameter is bound to `None`, then fed to a never-taken call so rustc
+// infers `return_val`'s and each parameter's concrete type. Nothing here ever
+// runs; it only exists to make `materialize_arg_tys`/`materialize_ret_tys`
+// type-check against the host method's real signature.
+fn emit_type_inference_preamble(func: &FuncDef) -> TokenStream {
     let call = {
         let params = func.params.iter().map(|param| {
             let ident = param.ident.clone();
@@ -147,36 +193,56 @@ fn emit_resolve_func_arm(func: &FuncDef) -> TokenStream {
         })
         .collect::<Vec<_>>();
 
+    quote! {
+        // initialize variables
+        #(#init)*
+
+        #[allow(unreachable_code)]
+        let return_val = if false {
+            // calling self for typeinference
+            Some(#call)
+        } else {
+            None
+        };
+    }
+}
+
+fn emit_resolve_func_arm(func: &FuncDef) -> TokenStream {
+    let index = func.index as usize;
+    let string_ident = &func.name;
+    let return_ty_span = func.return_ty.clone().unwrap_or_else(|| Span::call_site());
+
+    let preamble = emit_type_inference_preamble(func);
+
     let params_materialized_tys = func
         .params
         .iter()
         .map(|param| {
             let ident = &param.ident;
             let span = param.ident.span();
-            quote_spanned! {span=> materialize_arg_ty(#ident) }
+            quote_spanned! {span=> materialize_arg_tys(#ident) }
         })
         .collect::<Vec<_>>();
 
-    let materialized_return_ty = quote_spanned! { return_ty_span=>
-        materialize_ret_type(return_val)
+    let materialized_return_tys = quote_spanned! { return_ty_span=>
+        materialize_ret_tys(return_val)
     };
 
     quote! {
         if name == #string_ident {
-            // initialize variables
-            #(#init)*
-
-            #[allow(unreachable_code)]
-            let return_val = if false {
-                // calling self for typeinference
-                Some(#call)
-            } else {
-                None
-            };
+            #preamble
 
             // at this point types of all variables and return_val are inferred.
-            if signature.params() != &[#(#params_materialized_tys),*]
-                || signature.return_type() != #materialized_return_ty
+            let mut expected_params: Vec<ValueType> = Vec::new();
+            #(expected_params.extend_from_slice(#params_materialized_tys);)*
+            let expected_return_tys = #materialized_return_tys;
+
+            // `expected_return_tys` may hold more than one `ValueType`: a
+            // host method returning a tuple maps to a multi-value wasm
+            // result, which `Externals::invoke_index` dispatches as a
+            // `Vec<RuntimeValue>` (see `derive_externals`).
+            if signature.params() != &expected_params[..]
+                || signature.return_types() != expected_return_tys
             {
                 return Err(Error::Instantiation(
                     format!("Export {} has different signature {:?}", #string_ident, signature),
@@ -188,36 +254,234 @@ fn emit_resolve_func_arm(func: &FuncDef) -> TokenStream {
     }
 }
 
+fn emit_resolve_global_arm(global: &GlobalDef) -> TokenStream {
+    let string_ident = &global.name;
+    let ident = &global.ident;
+
+    quote! {
+        if name == #string_ident {
+            let global_ref = Self::#ident();
+            if descriptor.value_type() != global_ref.value_type()
+                || descriptor.is_mutable() != global_ref.is_mutable()
+            {
+                return Err(Error::Instantiation(
+                    format!("Export {} has different global descriptor {:?}", #string_ident, descriptor),
+                ));
+            }
+            return Ok(global_ref);
+        }
+    }
+}
+
+fn emit_resolve_memory_arm(memory: &MemoryDef) -> TokenStream {
+    let string_ident = &memory.name;
+    let ident = &memory.ident;
+
+    quote! {
+        if name == #string_ident {
+            let memory_ref = Self::#ident();
+            if !descriptor.is_compatible(&memory_ref) {
+                return Err(Error::Instantiation(
+                    format!("Export {} has different memory descriptor {:?}", #string_ident, descriptor),
+                ));
+            }
+            return Ok(memory_ref);
+        }
+    }
+}
+
+fn emit_resolve_table_arm(table: &TableDef) -> TokenStream {
+    let string_ident = &table.name;
+    let ident = &table.ident;
+
+    quote! {
+        if name == #string_ident {
+            let table_ref = Self::#ident();
+            if !descriptor.is_compatible(&table_ref) {
+                return Err(Error::Instantiation(
+                    format!("Export {} has different table descriptor {:?}", #string_ident, descriptor),
+                ));
+            }
+            return Ok(table_ref);
+        }
+    }
+}
+
 fn derive_module_resolver(ext_def: &ImplBlockDef, to: &mut TokenStream) {
     let (impl_generics, _, where_clause) = ext_def.generics.split_for_impl();
     let ty = &ext_def.ty;
 
-    let mut match_arms = vec![];
+    let mut func_arms = vec![];
     for func in &ext_def.funcs {
-        match_arms.push(emit_resolve_func_arm(func));
+        func_arms.push(emit_resolve_func_arm(func));
+    }
+
+    let mut global_arms = vec![];
+    for global in &ext_def.globals {
+        global_arms.push(emit_resolve_global_arm(global));
+    }
+
+    let mut memory_arms = vec![];
+    for memory in &ext_def.memories {
+        memory_arms.push(emit_resolve_memory_arm(memory));
+    }
+
+    let mut table_arms = vec![];
+    for table in &ext_def.tables {
+        table_arms.push(emit_resolve_table_arm(table));
     }
 
     (quote::quote! {
         impl #impl_generics #ty #where_clause {
             fn resolver() -> impl ModuleImportResolver {
-                // Use a closure to have an ability to use `Self` type
+                // Use closures to have an ability to use `Self` type
                 let resolve_func = |name: &str, signature: &Signature| -> Result<FuncRef, Error> {
-                    #(#match_arms)*
+                    #(#func_arms)*
 
                     Err(Error::Instantiation(
                         format!("Export {} not found", name),
                     ))
                 };
+                let resolve_global = |name: &str, descriptor: &GlobalDescriptor| -> Result<GlobalRef, Error> {
+                    #(#global_arms)*
 
-                struct Resolver(fn(&str, &Signature) -> Result<FuncRef, Error>);
+                    Err(Error::Instantiation(
+                        format!("Export {} not found", name),
+                    ))
+                };
+                let resolve_memory = |name: &str, descriptor: &MemoryDescriptor| -> Result<MemoryRef, Error> {
+                    #(#memory_arms)*
+
+                    Err(Error::Instantiation(
+                        format!("Export {} not found", name),
+                    ))
+                };
+                let resolve_table = |name: &str, descriptor: &TableDescriptor| -> Result<TableRef, Error> {
+                    #(#table_arms)*
+
+                    Err(Error::Instantiation(
+                        format!("Export {} not found", name),
+                    ))
+                };
+
+                struct Resolver(
+                    fn(&str, &Signature) -> Result<FuncRef, Error>,
+                    fn(&str, &GlobalDescriptor) -> Result<GlobalRef, Error>,
+                    fn(&str, &MemoryDescriptor) -> Result<MemoryRef, Error>,
+                    fn(&str, &TableDescriptor) -> Result<TableRef, Error>,
+                );
 				impl ModuleImportResolver for Resolver {
                     #[inline(always)]
 					fn resolve_func(&self, name: &str, signature: &Signature) -> Result<FuncRef, Error> {
                         (self.0)(name, signature)
 					}
+
+                    #[inline(always)]
+                    fn resolve_global(&self, name: &str, descriptor: &GlobalDescriptor) -> Result<GlobalRef, Error> {
+                        (self.1)(name, descriptor)
+                    }
+
+                    #[inline(always)]
+                    fn resolve_memory(&self, name: &str, descriptor: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+                        (self.2)(name, descriptor)
+                    }
+
+                    #[inline(always)]
+                    fn resolve_table(&self, name: &str, descriptor: &TableDescriptor) -> Result<TableRef, Error> {
+                        (self.3)(name, descriptor)
+                    }
 				}
-				Resolver(resolve_func)
+				Resolver(resolve_func, resolve_global, resolve_memory, resolve_table)
             }
         }
     }).to_tokens(to);
-}
\ No newline at end of file
+}
+
+// Produces the hashing steps for a single host function: the function name,
+// a `0x00` separator, the parameter type tags, a `0xFF` marker, and the
+// return type tag(s) (or `0` if there is none). Parameter and return types
+// are recovered with the same type-inference trick used by
+// `emit_resolve_func_arm` (see `emit_type_inference_preamble`), since the
+// derive has no other way to know them.
+//
+// `return_tys` may hash more than one tag: a multi-value return is a legal
+// ABI (`derive_externals` dispatches it as a `Vec<RuntimeValue>` and
+// `emit_resolve_func_arm` validates it against `signature.return_types()`),
+// so the digest must cover every return value, not just the first.
+fn emit_digest_func_block(func: &FuncDef) -> TokenStream {
+    let string_ident = &func.name;
+    let return_ty_span = func.return_ty.clone().unwrap_or_else(|| Span::call_site());
+
+    let preamble = emit_type_inference_preamble(func);
+
+    let params_materialized_tys = func
+        .params
+        .iter()
+        .map(|param| {
+            let ident = &param.ident;
+            let span = param.ident.span();
+            quote_spanned! {span=> materialize_arg_tys(#ident) }
+        })
+        .collect::<Vec<_>>();
+
+    let materialized_return_tys = quote_spanned! { return_ty_span=>
+        materialize_ret_tys(return_val)
+    };
+
+    quote! {
+        {
+            #preamble
+
+            // at this point types of all variables and return_val are inferred.
+            hasher.update(#string_ident.as_bytes());
+            hasher.update(&[0x00]);
+            for param_tys in &[#(#params_materialized_tys),*] {
+                for param_ty in param_tys.iter() {
+                    hasher.update(&[value_type_tag(*param_ty)]);
+                }
+            }
+            hasher.update(&[0xFF]);
+            let return_tys = #materialized_return_tys;
+            if return_tys.is_empty() {
+                hasher.update(&[0]);
+            } else {
+                for return_ty in return_tys.iter() {
+                    hasher.update(&[value_type_tag(*return_ty)]);
+                }
+            }
+        }
+    }
+}
+
+fn derive_abi_digest(ext_def: &ImplBlockDef, to: &mut TokenStream) {
+    let (impl_generics, _, where_clause) = ext_def.generics.split_for_impl();
+    let ty = &ext_def.ty;
+
+    let mut funcs_by_index = ext_def.funcs.iter().collect::<Vec<_>>();
+    funcs_by_index.sort_by_key(|func| func.index);
+
+    let blocks = funcs_by_index
+        .into_iter()
+        .map(emit_digest_func_block)
+        .collect::<Vec<_>>();
+
+    (quote::quote! {
+        impl #impl_generics #ty #where_clause {
+            /// Computes a stable digest of the host interface's ABI: the name,
+            /// parameter types and return type(s) of every exposed function, in
+            /// `index` order. Two host implementations with matching digests
+            /// expose the same set of host functions with the same signatures,
+            /// even if their Rust implementations differ. Host-provided
+            /// globals, memories and tables are not part of the digest.
+            pub fn abi_digest() -> [u8; 32] {
+                let mut hasher = Sha3_256::new();
+                #(#blocks)*
+                let digest = hasher.finalize();
+                let mut out = [0u8; 32];
+                out.copy_from_slice(&digest);
+                out
+            }
+        }
+    })
+    .to_tokens(to);
+}